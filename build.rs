@@ -0,0 +1,10 @@
+// Generate the messages and client/server of the StockMarket gRPC service from
+// proto/StockMarket.proto. protoc comes from protoc-bin-vendored unless PROTOC
+// points at one already, so building needs no system install.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_build::compile_protos("proto/StockMarket.proto")?;
+    Ok(())
+}
@@ -0,0 +1,447 @@
+// RabbitMQ connection handling shared by the market and the brokers
+
+use futures::{StreamExt, TryStreamExt};
+use lapin::{
+    message::Delivery,
+    options::{BasicConsumeOptions, BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::Confirmation,
+    tcp::{OwnedIdentity, OwnedTLSConfig},
+    types::FieldTable,
+    BasicProperties, Channel, Connection, ConnectionProperties, ConnectionState, Consumer,
+};
+use metrics::counter;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{error, info, warn};
+
+// Upper bound for the delay between two reconnection attempts
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Retries of a message RabbitMQ nacked, and the pause before each
+const MAX_NACK_RETRIES: u32 = 3;
+const NACK_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// The exchanges and queues a process declares on every new connection
+pub trait Declare {
+    fn declare(
+        &self,
+        channel: &Channel,
+        durable: bool,
+    ) -> impl Future<Output = Result<(), lapin::Error>> + Send;
+}
+
+// lapin takes the TLS settings by value on every connect
+fn copy_tls(tls: &OwnedTLSConfig) -> OwnedTLSConfig {
+    OwnedTLSConfig {
+        identity: tls.identity.as_ref().map(|identity| OwnedIdentity {
+            der: identity.der.clone(),
+            password: identity.password.clone(),
+        }),
+        cert_chain: tls.cert_chain.clone(),
+    }
+}
+
+// Connect to RabbitMQ, retrying with an exponential backoff starting at 1s.
+// Each wait gets up to 50% random jitter so restarted processes don't reconnect in lockstep.
+pub async fn connect_with_retry(
+    addr: &str,
+    max_retries: u32,
+    tls: Option<&OwnedTLSConfig>,
+) -> Result<Connection, lapin::Error> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        let tls = tls.map(copy_tls).unwrap_or_default();
+        match Connection::connect_with_config(addr, ConnectionProperties::default(), tls).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) if attempt < max_retries => {
+                attempt += 1;
+                let wait = delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.5));
+                warn!(
+                    "Connection to RabbitMQ failed: {} (retry {}/{} in {:?})",
+                    e, attempt, max_retries, wait
+                );
+                time::sleep(wait).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A publish that failed while RabbitMQ was unreachable, replayed after reconnecting
+struct PendingPublish {
+    exchange: String,
+    routing_key: String,
+    payload: Vec<u8>,
+    properties: BasicProperties,
+}
+
+// Owns the RabbitMQ connection and a shared publishing channel with publisher confirms,
+// reconnecting and re-declaring the topology whenever the connection or channel has
+// dropped. Consumers get channels of their own from `consumer_channel`.
+pub struct ConnectionManager<T> {
+    addr: String,
+    tls: Option<OwnedTLSConfig>, // None for a plain connection, or amqps:// with defaults
+    max_retries: u32,
+    durable: bool, // durable topology and persistent messages, the same in every process
+    pub topology: T,
+    state: Mutex<Option<(Connection, Channel)>>,
+    pending: Mutex<VecDeque<PendingPublish>>,
+    publish_buffer_limit: usize,
+    closed: AtomicBool, // set by close(); no reconnecting after that
+}
+
+impl<T: Declare> ConnectionManager<T> {
+    // A manager that connects on first use
+    pub fn new(
+        addr: &str,
+        max_retries: u32,
+        durable: bool,
+        topology: T,
+        publish_buffer_limit: usize,
+        tls: Option<OwnedTLSConfig>,
+    ) -> Self {
+        ConnectionManager {
+            addr: addr.to_string(),
+            tls,
+            max_retries,
+            durable,
+            topology,
+            state: Mutex::new(None),
+            pending: Mutex::new(VecDeque::new()),
+            publish_buffer_limit,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn connect(
+        addr: &str,
+        max_retries: u32,
+        durable: bool,
+        topology: T,
+        publish_buffer_limit: usize,
+        tls: Option<OwnedTLSConfig>,
+    ) -> Result<Self, lapin::Error> {
+        let manager = Self::new(
+            addr,
+            max_retries,
+            durable,
+            topology,
+            publish_buffer_limit,
+            tls,
+        );
+        manager.channel().await?;
+        Ok(manager)
+    }
+
+    // Base properties for published messages: persistent (delivery mode 2) when durable
+    pub fn message_properties(&self) -> BasicProperties {
+        if self.durable {
+            BasicProperties::default().with_delivery_mode(2)
+        } else {
+            BasicProperties::default()
+        }
+    }
+
+    pub async fn channel(&self) -> Result<Channel, lapin::Error> {
+        let mut state = self.state.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(lapin::Error::InvalidConnectionState(
+                ConnectionState::Closed,
+            ));
+        }
+
+        if let Some((conn, channel)) = state.as_ref() {
+            if conn.status().connected() && channel.status().connected() {
+                let channel = channel.clone();
+                self.flush_pending(&channel).await;
+                return Ok(channel);
+            }
+            warn!("RabbitMQ connection lost, reconnecting");
+        }
+
+        let conn = connect_with_retry(&self.addr, self.max_retries, self.tls.as_ref()).await?;
+        let channel = conn.create_channel().await?;
+        // publisher confirms, so a message RabbitMQ refuses is noticed and retried
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        self.topology.declare(&channel, self.durable).await?;
+        *state = Some((conn, channel.clone()));
+        self.flush_pending(&channel).await;
+        Ok(channel)
+    }
+
+    // Close the connection with an AMQP close handshake, so RabbitMQ logs a clean
+    // client close rather than a reset connection. Consumers' channels close with it.
+    pub async fn close(&self) {
+        let mut state = self.state.lock().await;
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some((conn, channel)) = state.take() {
+            if let Err(e) = channel.close(200, "Shutting down").await {
+                warn!("Failed to close the publishing channel: {}", e);
+            }
+            if let Err(e) = conn.close(200, "Shutting down").await {
+                warn!("Failed to close the RabbitMQ connection: {}", e);
+            }
+        }
+        let pending = self.pending.lock().await.len();
+        if pending > 0 {
+            warn!("Dropped {} buffered messages on shutdown", pending);
+        }
+    }
+
+    // A dedicated channel for one consumer, so deliveries and their prefetch window
+    // are not multiplexed with publishing on the shared channel
+    pub async fn consumer_channel(&self) -> Result<Channel, lapin::Error> {
+        self.channel().await?;
+        let state = self.state.lock().await;
+        // close() may have taken the connection since
+        let Some((conn, _)) = state.as_ref() else {
+            return Err(lapin::Error::InvalidConnectionState(
+                ConnectionState::Closed,
+            ));
+        };
+        conn.create_channel().await
+    }
+
+    // Start consuming `queue` on a channel of its own, retrying until that works.
+    // `prepare` runs on every new channel first, e.g. to set a prefetch count or to
+    // declare queues that come and go with the connection.
+    pub async fn subscribe<P, PF>(
+        &self,
+        queue: &str,
+        what: &str,
+        options: BasicConsumeOptions,
+        prepare: &P,
+    ) -> (Channel, Consumer)
+    where
+        P: Fn(Channel) -> PF,
+        PF: Future<Output = Result<(), lapin::Error>>,
+    {
+        loop {
+            let channel = match self.consumer_channel().await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    warn!(
+                        "RabbitMQ channel unavailable, retrying in {:?}: {}",
+                        MAX_RECONNECT_DELAY, e
+                    );
+                    time::sleep(MAX_RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+
+            if let Err(e) = prepare(channel.clone()).await {
+                error!("Failed to prepare consuming {}: {}", queue, e);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+
+            match channel
+                .basic_consume(
+                    queue,
+                    &format!("{}_consumer_tag", queue),
+                    options,
+                    FieldTable::default(),
+                )
+                .await
+            {
+                Ok(consumer) => return (channel, consumer),
+                Err(e) => {
+                    error!("Failed to start consuming {}: {}", what, e);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    // Hand every delivery of `queue` to `handle`, acked automatically, re-subscribing
+    // whenever the consumer stops, e.g. after the connection dropped. Returns once
+    // `handle` breaks.
+    pub async fn consume_with_reconnect<P, PF, H, HF>(
+        &self,
+        queue: &str,
+        what: &str,
+        prepare: P,
+        mut handle: H,
+    ) where
+        P: Fn(Channel) -> PF,
+        PF: Future<Output = Result<(), lapin::Error>>,
+        H: FnMut(Delivery) -> HF,
+        HF: Future<Output = ControlFlow<()>>,
+    {
+        loop {
+            let options = BasicConsumeOptions {
+                no_ack: true,
+                ..BasicConsumeOptions::default()
+            };
+            let (_, consumer) = self.subscribe(queue, what, options, &prepare).await;
+            let mut consumer_stream = consumer.into_stream();
+            while let Some(delivery) = consumer_stream.next().await {
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        error!("Error receiving {}: {}", what, e);
+                        break;
+                    }
+                };
+                if handle(delivery).await.is_break() {
+                    return;
+                }
+            }
+
+            warn!("Consumer of {} stopped, re-subscribing", what);
+            time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    // Like consume_with_reconnect, for a queue declared with the topology and a
+    // handler that never stops. Never returns.
+    pub async fn consume_forever<H, HF>(&self, queue: &str, what: &str, mut handle: H)
+    where
+        H: FnMut(Delivery) -> HF,
+        HF: Future<Output = ()>,
+    {
+        self.consume_with_reconnect(
+            queue,
+            what,
+            |_| async { Ok(()) },
+            |delivery| {
+                let handled = handle(delivery);
+                async move {
+                    handled.await;
+                    ControlFlow::Continue(())
+                }
+            },
+        )
+        .await
+    }
+
+    // Publish on the current channel. If that fails the message is buffered and
+    // replayed once the connection is back; past `publish_buffer_limit` the oldest
+    // buffered message is dropped. Errors only when the message could not be buffered.
+    pub async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: Vec<u8>,
+        properties: BasicProperties,
+    ) -> Result<(), lapin::Error> {
+        let error = match self.channel().await {
+            Ok(channel) => {
+                match publish_confirmed(&channel, exchange, routing_key, &payload, &properties)
+                    .await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        counter!("rabbitmq_publish_errors_total").increment(1);
+        if self.publish_buffer_limit == 0 {
+            return Err(error);
+        }
+
+        error!("Failed to publish to {}: {}", routing_key, error);
+        let mut pending = self.pending.lock().await;
+        if pending.len() == self.publish_buffer_limit {
+            pending.pop_front();
+            warn!("Publish buffer full, dropped the oldest message");
+        }
+        pending.push_back(PendingPublish {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            payload,
+            properties,
+        });
+        warn!(
+            "Buffered message for {} until RabbitMQ is back ({} pending)",
+            routing_key,
+            pending.len()
+        );
+        Ok(())
+    }
+
+    // Replay buffered publishes in order, each confirmed like a fresh publish, stopping
+    // at the first failure
+    async fn flush_pending(&self, channel: &Channel) {
+        let mut pending = self.pending.lock().await;
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut sent = 0;
+        while let Some(message) = pending.pop_front() {
+            if let Err(e) = publish_confirmed(
+                channel,
+                &message.exchange,
+                &message.routing_key,
+                &message.payload,
+                &message.properties,
+            )
+            .await
+            {
+                error!("Failed to replay buffered message: {}", e);
+                pending.push_front(message);
+                break;
+            }
+            sent += 1;
+        }
+        info!(
+            "Replayed {} buffered messages ({} still pending)",
+            sent,
+            pending.len()
+        );
+    }
+}
+
+// Publish and wait for RabbitMQ's confirm. A NACKed message is retried up to
+// MAX_NACK_RETRIES times; after that it is dropped, as buffering it won't help.
+async fn publish_confirmed(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+    properties: &BasicProperties,
+) -> Result<(), lapin::Error> {
+    let mut attempts = 0;
+    loop {
+        if attempts > 0 {
+            time::sleep(NACK_RETRY_DELAY).await;
+        }
+        attempts += 1;
+
+        let confirmation = channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                payload,
+                properties.clone(),
+            )
+            .await?
+            .await?;
+        if let Confirmation::Nack(_) = confirmation {
+            if attempts > MAX_NACK_RETRIES {
+                counter!("publish_nack_total").increment(1);
+                error!(
+                    "RabbitMQ refused message for {} {} times, dropping it",
+                    routing_key, attempts
+                );
+            } else {
+                warn!("RabbitMQ nacked message for {}, retrying", routing_key);
+                continue;
+            }
+        }
+        return Ok(());
+    }
+}
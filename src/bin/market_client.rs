@@ -0,0 +1,71 @@
+use futures::StreamExt;
+use std::error::Error;
+
+mod stock_market {
+    tonic::include_proto!("stock_market");
+}
+
+use stock_market::{
+    stock_market_client::StockMarketClient, Empty, OrderRequest, PriceStreamRequest, Stock,
+};
+
+const USAGE: &str = "usage: market_client stocks
+       market_client order <buy|sell> <stock_id> <quantity> [broker_id] [limit_price]
+       market_client stream [stock_id...]";
+
+fn print_stock(stock: &Stock) {
+    println!(
+        "{:<4} {:<8} sell {:>10.2} buy {:>10.2} {:<3} available {}",
+        stock.id,
+        stock.name,
+        stock.sell_price,
+        stock.buy_price,
+        stock.currency,
+        stock.available_stock
+    );
+}
+
+// Command-line client of the market's gRPC service, e.g. to check a running `stocks`
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let addr = std::env::var("GRPC_ADDR").unwrap_or_else(|_| "127.0.0.1:50051".into());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut client = StockMarketClient::connect(format!("http://{}", addr)).await?;
+
+    match args.first().map(String::as_str) {
+        Some("stocks") => {
+            let stocks = client.get_stocks(Empty {}).await?.into_inner().stocks;
+            stocks.iter().for_each(print_stock);
+        }
+        Some("order") if args.len() >= 4 => {
+            let request = OrderRequest {
+                action: args[1].clone(),
+                stock_id: args[2].clone(),
+                quantity: args[3].parse()?,
+                broker_id: args.get(4).cloned().unwrap_or_default(),
+                order_id: String::new(),
+                limit_price: args.get(5).map(|price| price.parse()).transpose()?,
+                allow_partial: false,
+            };
+            let response = client.place_order(request).await?.into_inner();
+            println!("Order {} enqueued", response.order_id);
+        }
+        Some("stream") => {
+            let request = PriceStreamRequest {
+                stock_ids: args[1..].to_vec(),
+            };
+            let mut updates = client.stream_prices(request).await?.into_inner();
+            while let Some(update) = updates.next().await {
+                let update = update?;
+                println!("-- tick at {}", update.timestamp);
+                update.stocks.iter().for_each(print_stock);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,2 @@
+// Code shared by the market and broker binaries
+pub mod amqp;
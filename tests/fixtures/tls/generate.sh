@@ -0,0 +1,33 @@
+#!/bin/sh
+# Self-signed CA, RabbitMQ server and client certificates for testing `stocks --tls`.
+# Writes ca.pem, server.pem, server-key.pem, client.pem and client-key.pem next to this
+# script. Point RabbitMQ's ssl_options at the CA and server files (listening on 5671,
+# with verify_peer), then run the market with:
+#   AMQP_CA_CERT=ca.pem AMQP_CLIENT_CERT=client.pem AMQP_CLIENT_KEY=client-key.pem \
+#       cargo run --bin stocks -- --tls
+# The committed files, used by the stocks TLS test, were made with DAYS=36500.
+set -eu
+cd "$(dirname "$0")"
+DAYS=${DAYS:-30}
+HOST=${HOST:-localhost}
+
+openssl req -x509 -newkey rsa:2048 -nodes -days "$DAYS" \
+    -subj "/CN=stock_trading_system test CA" \
+    -keyout ca-key.pem -out ca.pem
+
+sign() {
+    name=$1
+    subject=$2
+    extensions=$3
+    openssl req -newkey rsa:2048 -nodes -subj "$subject" \
+        -keyout "$name-key.pem" -out "$name.csr"
+    printf '%s\n' "$extensions" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+        -days "$DAYS" -extfile "$name.ext" -out "$name.pem"
+    rm "$name.csr" "$name.ext"
+}
+
+sign server "/CN=$HOST" "subjectAltName=DNS:$HOST,IP:127.0.0.1
+extendedKeyUsage=serverAuth"
+sign client "/CN=stocks" "extendedKeyUsage=clientAuth"
+rm -f ca.srl
@@ -0,0 +1,146 @@
+// Runs the market against the RabbitMQ at AMQP_ADDR (amqp://127.0.0.1:5672/%2f by
+// default), places an order through the HTTP API and watches its counter on /metrics.
+// Skipped when no RabbitMQ is reachable.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const AMQP_ADDR: &str = "amqp://127.0.0.1:5672/%2f";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Kills the market when the test ends, passing or not
+struct Market(Child);
+
+impl Drop for Market {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// host:port of an amqp:// address
+fn amqp_socket(addr: &str) -> Option<SocketAddr> {
+    let authority = addr.strip_prefix("amqp://")?.split('/').next()?;
+    let host_port = authority.rsplit('@').next()?;
+    let host_port = if host_port.contains(':') {
+        host_port.to_string()
+    } else {
+        format!("{}:5672", host_port)
+    };
+    host_port.to_socket_addrs().ok()?.next()
+}
+
+// Body of a 2xx answer to `method path`, or None if the server isn't answering
+fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> Option<String> {
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(1)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    head.split(' ')
+        .nth(1)
+        .is_some_and(|status| status.starts_with('2'))
+        .then(|| body.to_string())
+}
+
+// Retry `f` until it returns Some or `timeout` runs out
+fn wait_for<T>(timeout: Duration, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = f() {
+            return Some(value);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// Orders counted for one action on one stock, over all outcomes
+fn orders_counted(metrics: &str, action: &str, stock_id: &str) -> u64 {
+    let prefix = format!(
+        "stock_orders_total{{action=\"{}\",stock_id=\"{}\",",
+        action, stock_id
+    );
+    metrics
+        .lines()
+        .filter(|line| line.starts_with(&prefix))
+        .filter_map(|line| line.rsplit(' ').next()?.parse::<u64>().ok())
+        .sum()
+}
+
+#[test]
+fn placed_orders_are_counted_on_the_metrics_endpoint() {
+    let amqp_addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| AMQP_ADDR.to_string());
+    let reachable = amqp_socket(&amqp_addr)
+        .is_some_and(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok());
+    if !reachable {
+        eprintln!("skipping: no RabbitMQ at {}", amqp_addr);
+        return;
+    }
+
+    // a directory of its own for the transaction log and price history
+    let dir = std::env::temp_dir().join(format!("metrics_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (api, metrics) = (free_port(), free_port());
+    let _market = Market(
+        Command::new(env!("CARGO_BIN_EXE_stocks"))
+            .current_dir(&dir)
+            .env("AMQP_ADDR", &amqp_addr)
+            .env("API_ADDR", api.to_string())
+            .env("METRICS_ADDR", metrics.to_string())
+            .env("PRICE_WS_ADDR", free_port().to_string())
+            .env("GRPC_ADDR", free_port().to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let stocks = wait_for(STARTUP_TIMEOUT, || http(api, "GET", "/stocks", ""))
+        .expect("the HTTP API did not come up");
+    let stocks: serde_json::Value = serde_json::from_str(&stocks).unwrap();
+    let stock = &stocks["stocks"][0];
+    let stock_id = stock["id"].as_str().unwrap().to_string();
+    let before = wait_for(STARTUP_TIMEOUT, || http(metrics, "GET", "/metrics", ""))
+        .expect("the metrics endpoint did not come up");
+    let before = orders_counted(&before, "buy", &stock_id);
+
+    // quoted at the listed prices, as a broker would
+    let order = serde_json::json!({
+        "action": "buy", "id": stock_id, "name": stock["name"], "quantity": 1,
+        "sell_price": stock["sell_price"], "buy_price": stock["buy_price"],
+        "broker_id": "metrics_test"
+    });
+    http(api, "POST", "/orders", &order.to_string()).expect("the order was not accepted");
+
+    let counted = wait_for(Duration::from_secs(10), || {
+        let metrics = http(metrics, "GET", "/metrics", "")?;
+        let counted = orders_counted(&metrics, "buy", &stock_id);
+        (counted > before).then_some(counted)
+    });
+    assert_eq!(counted, Some(before + 1));
+    let _ = std::fs::remove_dir_all(&dir);
+}